@@ -1,6 +1,49 @@
 //! CommandPool module docs.
 
-use crate::{buffer::*, capability::*};
+use crate::{buffer::*, capability::*, family::SubmissionIndex};
+
+/// Number of command buffers allocated into `available` at once
+/// when `OwningCommandPool::acquire_buffer` finds it empty.
+const GROW_AMOUNT: usize = 20;
+
+/// Remove and return every entry whose `SubmissionIndex` is at or before
+/// `last_done_index`, via `swap_remove` to avoid shifting the rest.
+fn retire<T>(pending: &mut Vec<(T, SubmissionIndex)>, last_done_index: SubmissionIndex) -> Vec<T> {
+    let mut done = Vec::new();
+    let mut i = 0;
+    while i < pending.len() {
+        if pending[i].1 <= last_done_index {
+            done.push(pending.swap_remove(i).0);
+        } else {
+            i += 1;
+        }
+    }
+    done
+}
+
+/// Type-erased handle to a GPU resource, for manual lifetime tracking.
+///
+/// Not wired into command buffer recording: nothing calls
+/// [`OwningCommandPool::keep_alive`](struct.OwningCommandPool.html#method.keep_alive)
+/// automatically, so this does not by itself guarantee a resource outlives
+/// the submissions that reference it.
+pub type ResourceHandle = std::sync::Arc<dyn std::any::Any + Send + Sync>;
+
+/// Raw creation flags required by a [`Reset`] marker type.
+pub trait ResetFlags: Reset {
+    /// Flags a pool using this reset marker must be created with.
+    const FLAGS: gfx_hal::pool::CommandPoolCreateFlags;
+}
+
+impl ResetFlags for NoIndividualReset {
+    const FLAGS: gfx_hal::pool::CommandPoolCreateFlags =
+        gfx_hal::pool::CommandPoolCreateFlags::empty();
+}
+
+impl ResetFlags for IndividualReset {
+    const FLAGS: gfx_hal::pool::CommandPoolCreateFlags =
+        gfx_hal::pool::CommandPoolCreateFlags::RESET_INDIVIDUAL;
+}
 
 /// Simple pool wrapper.
 /// Doesn't provide any guarantees.
@@ -43,6 +86,31 @@ where
         }
     }
 
+    /// Create a command pool for `family`. `transient` hints that buffers
+    /// will be recorded once and quickly reset.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`from_raw`](#method.from_raw).
+    pub unsafe fn create(
+        device: &impl gfx_hal::Device<B>,
+        family: gfx_hal::queue::QueueFamilyId,
+        capability: C,
+        reset: R,
+        transient: bool,
+    ) -> Self
+    where
+        R: ResetFlags,
+    {
+        let mut flags = R::FLAGS;
+        if transient {
+            flags |= gfx_hal::pool::CommandPoolCreateFlags::TRANSIENT;
+        }
+
+        let raw = device.create_command_pool(family, flags);
+        Self::from_raw(raw, capability, reset, family)
+    }
+
     /// Allocate new command buffers.
     pub fn allocate_buffers<L: Level>(
         &mut self,
@@ -90,14 +158,23 @@ where
         }
     }
 
-    /// Reset all buffers of this pool.
+    /// Reset all buffers of this pool, recycling their memory for reuse.
     ///
     /// # Safety
     ///
     /// All buffers allocated from this pool must be marked reset.
     /// See [`CommandBuffer::mark_reset`](struct.Command buffer.html#method.mark_reset)
     pub unsafe fn reset(&mut self) {
-        gfx_hal::pool::RawCommandPool::reset(&mut self.raw);
+        gfx_hal::pool::RawCommandPool::reset(&mut self.raw, false);
+    }
+
+    /// Reset all buffers, releasing pool-owned memory back to the driver.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`reset`](#method.reset).
+    pub unsafe fn reset_release_resources(&mut self) {
+        gfx_hal::pool::RawCommandPool::reset(&mut self.raw, true);
     }
 
     /// Dispose of command pool.
@@ -147,74 +224,74 @@ where
 /// It can be used to borrow buffers one by one.
 /// All buffers will be reset together via pool.
 /// Prior resetting user must ensure all buffers are complete.
+///
+/// When `R` is [`IndividualReset`] the pool additionally tracks in-flight
+/// buffers by [`SubmissionIndex`] so individual buffers can be recycled via
+/// [`release`](#method.release) and [`maintain`](#method.maintain) as soon as
+/// their submission retires, without waiting for every buffer to complete.
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
-pub struct OwningCommandPool<B: gfx_hal::Backend, C = gfx_hal::QueueType, L = PrimaryLevel> {
-    inner: CommandPool<B, C>,
+pub struct OwningCommandPool<B: gfx_hal::Backend, C = gfx_hal::QueueType, L = PrimaryLevel, R = NoIndividualReset> {
+    inner: CommandPool<B, C, R>,
     level: L,
     #[derivative(Debug = "ignore")]
-    buffers: Vec<B::CommandBuffer>,
-    next: usize,
+    available: Vec<B::CommandBuffer>,
+    #[derivative(Debug = "ignore")]
+    pending: Vec<(B::CommandBuffer, SubmissionIndex)>,
+    #[derivative(Debug = "ignore")]
+    keep_alive: Vec<(ResourceHandle, SubmissionIndex)>,
 }
 
-impl<B, C, L> OwningCommandPool<B, C, L>
+impl<B, C, L, R> OwningCommandPool<B, C, L, R>
 where
     B: gfx_hal::Backend,
+    R: Reset,
 {
     /// Wrap simple pool into owning version.
     ///
     /// # Safety
     ///
     /// * All buffers allocated from this pool must be [freed](#method.free_buffers).
-    pub unsafe fn from_inner(inner: CommandPool<B, C>, level: L) -> Self {
+    pub unsafe fn from_inner(inner: CommandPool<B, C, R>, level: L) -> Self {
         OwningCommandPool {
             inner,
             level,
-            buffers: Vec::new(),
-            next: 0,
-        }
-    }
-
-    /// Reserve at least `count` buffers.
-    /// Allocate if there are not enough unused buffers.
-    pub fn reserve(&mut self, count: usize)
-    where
-        L: Level,
-    {
-        let total = self.next + count;
-        if total >= self.buffers.len() {
-            let add = total - self.buffers.len();
-
-            // TODO: avoid Vec allocation.
-            self.buffers.extend(
-                unsafe {
-                    gfx_hal::pool::RawCommandPool::allocate(
-                        &mut self.inner.raw,
-                        add,
-                        self.level.level(),
-                    )
-                }
-            );
+            available: Vec::new(),
+            pending: Vec::new(),
+            keep_alive: Vec::new(),
         }
     }
 
     /// Acquire next unused command buffer from pool.
     ///
+    /// Pulls from the pool of [`available`](#field.available) buffers,
+    /// growing it by [`GROW_AMOUNT`] when empty.
+    ///
     /// # Safety
     ///
     /// * Acquired buffer must be [released](struct.Command buffer#method.release) when no longer needed.
     pub fn acquire_buffer(
         &mut self,
-    ) -> CommandBuffer<B, C, InitialState, L>
+    ) -> CommandBuffer<B, C, InitialState, L, R>
     where
         L: Level,
         C: Capability,
     {
-        self.reserve(1);
-        self.next += 1;
+        if self.available.is_empty() {
+            self.available.extend(unsafe {
+                gfx_hal::pool::RawCommandPool::allocate(
+                    &mut self.inner.raw,
+                    GROW_AMOUNT,
+                    self.level.level(),
+                )
+            });
+        }
+
+        let raw = self.available.pop().expect("`available` was just replenished above");
+
         unsafe {
             CommandBuffer::from_raw(
-                &mut self.buffers[self.next - 1],
+                raw,
                 self.inner.capability,
                 InitialState,
                 self.level,
@@ -236,7 +313,21 @@ where
     /// * Any primary buffer that references secondary buffer from this pool will be invalidated.
     pub unsafe fn reset(&mut self) {
         self.inner.reset();
-        self.next = 0;
+        self.available.extend(self.pending.drain(..).map(|(buffer, _)| buffer));
+        self.keep_alive.clear();
+    }
+
+    /// Reset all buffers, releasing pool-owned memory back to the driver.
+    /// Drops the now-stale `available`/`pending` handles.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`reset`](#method.reset).
+    pub unsafe fn reset_release_resources(&mut self) {
+        self.inner.reset_release_resources();
+        self.available = Vec::new();
+        self.pending = Vec::new();
+        self.keep_alive.clear();
     }
 
     /// Dispose of command pool.
@@ -246,28 +337,29 @@ where
     /// Same as for [`CommandPool::reset`](#method.reset).
     pub unsafe fn dispose(mut self, device: &impl gfx_hal::Device<B>) {
         self.reset();
-        if !self.buffers.is_empty() {
-            gfx_hal::pool::RawCommandPool::free(&mut self.inner.raw, self.buffers);
+        if !self.available.is_empty() {
+            gfx_hal::pool::RawCommandPool::free(&mut self.inner.raw, self.available);
         }
 
         self.inner.dispose(device);
     }
 
     /// Convert capability level.
-    pub fn with_value_capability(self) -> OwningCommandPool<B, gfx_hal::QueueType, L>
+    pub fn with_value_capability(self) -> OwningCommandPool<B, gfx_hal::QueueType, L, R>
     where
         C: Capability,
     {
         OwningCommandPool {
             inner: self.inner.with_value_capability(),
             level: self.level,
-            buffers: self.buffers,
-            next: self.next,
+            available: self.available,
+            pending: self.pending,
+            keep_alive: self.keep_alive,
         }
     }
 
     /// Convert capability level.
-    pub fn with_capability<U>(self) -> Result<OwningCommandPool<B, U, L>, Self>
+    pub fn with_capability<U>(self) -> Result<OwningCommandPool<B, U, L, R>, Self>
     where
         C: Supports<U>,
     {
@@ -275,15 +367,296 @@ where
             Ok(inner) => Ok(OwningCommandPool {
                 inner,
                 level: self.level,
-                buffers: self.buffers,
-                next: self.next,
+                available: self.available,
+                pending: self.pending,
+                keep_alive: self.keep_alive,
             }),
             Err(inner) => Err(OwningCommandPool {
                 inner,
                 level: self.level,
-                buffers: self.buffers,
-                next: self.next,
+                available: self.available,
+                pending: self.pending,
+                keep_alive: self.keep_alive,
             })
         }
     }
 }
+
+impl<B, C, L> OwningCommandPool<B, C, L, IndividualReset>
+where
+    B: gfx_hal::Backend,
+{
+    /// Return a buffer to the pool once its submission is known complete,
+    /// making it available to [`acquire_buffer`](#method.acquire_buffer) again.
+    ///
+    /// Until that submission is confirmed done (via [`maintain`](#method.maintain))
+    /// the raw buffer is kept in `pending` rather than `available`, so it can
+    /// never be handed back out while it may still be executing on the GPU.
+    pub fn release(&mut self, buffer: B::CommandBuffer, submission: SubmissionIndex) {
+        self.pending.push((buffer, submission));
+    }
+
+    /// Keep `resource` alive until `submission` is known complete.
+    /// Caller must call this by hand for every resource a recorded buffer
+    /// references; it is not invoked automatically during recording.
+    pub fn keep_alive(&mut self, submission: SubmissionIndex, resource: ResourceHandle) {
+        self.keep_alive.push((resource, submission));
+    }
+
+    /// Recycle every pending buffer whose submission has retired, and drop
+    /// the keep-alive handles of resources only referenced by submissions
+    /// that have now completed.
+    ///
+    /// `last_done_index` is the highest [`SubmissionIndex`] known to have
+    /// completed on the GPU; any pending buffer at or before it is reset and
+    /// moved into `available`.
+    pub fn maintain(&mut self, last_done_index: SubmissionIndex) {
+        for mut buffer in retire(&mut self.pending, last_done_index) {
+            unsafe {
+                gfx_hal::command::RawCommandBuffer::reset(&mut buffer, false);
+            }
+            self.available.push(buffer);
+        }
+
+        retire(&mut self.keep_alive, last_done_index);
+    }
+}
+
+/// Per-key lazily-created slots, each independently lockable.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+struct KeyedSlots<K, V> {
+    #[derivative(Debug = "ignore")]
+    slots: std::sync::RwLock<std::collections::HashMap<K, std::sync::Mutex<V>>>,
+}
+
+impl<K: Eq + std::hash::Hash + Copy, V> KeyedSlots<K, V> {
+    fn new() -> Self {
+        KeyedSlots {
+            slots: std::sync::RwLock::new(std::collections::HashMap::new()),
+        }
+    }
+
+    /// Ensure a slot for `key` exists, creating it with `create` if not.
+    fn ensure(&self, key: K, create: impl FnOnce() -> V) {
+        if !self.slots.read().unwrap().contains_key(&key) {
+            let value = create();
+            self.slots.write().unwrap().entry(key).or_insert_with(|| std::sync::Mutex::new(value));
+        }
+    }
+
+    /// Run `f` with exclusive access to `key`'s slot.
+    fn with<T>(&self, key: K, f: impl FnOnce(&mut V) -> T) -> T {
+        let slots = self.slots.read().unwrap();
+        let mut value = slots.get(&key).expect("slot missing; call `ensure` first").lock().unwrap();
+        f(&mut value)
+    }
+
+    /// Run `f` against every slot.
+    fn for_each(&self, mut f: impl FnMut(&mut V)) {
+        let slots = self.slots.read().unwrap();
+        for value in slots.values() {
+            f(&mut value.lock().unwrap());
+        }
+    }
+
+    fn into_values(self) -> impl Iterator<Item = V> {
+        self.slots.into_inner().unwrap().into_iter().map(|(_, v)| v.into_inner().unwrap())
+    }
+}
+
+/// Hands out one [`OwningCommandPool`] per thread.
+/// Command pools are not thread-safe; lazily creates a pool per thread
+/// the first time that thread acquires one.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct CommandPoolManager<B: gfx_hal::Backend, C = gfx_hal::QueueType, R = NoIndividualReset> {
+    family: gfx_hal::queue::QueueFamilyId,
+    capability: C,
+    reset: R,
+    flags: gfx_hal::pool::CommandPoolCreateFlags,
+    pools: KeyedSlots<std::thread::ThreadId, OwningCommandPool<B, C, PrimaryLevel, R>>,
+}
+
+impl<B, C, R> CommandPoolManager<B, C, R>
+where
+    B: gfx_hal::Backend,
+    C: Capability + Copy,
+    R: Reset + Copy,
+{
+    /// Create an empty manager that will create per-thread pools for
+    /// `family` with the given `capability`, `reset` behavior and raw
+    /// creation `flags` on first use.
+    ///
+    /// # Safety
+    ///
+    /// * `capability` must be a subset of the capabilities of the `family`
+    ///   identified by `family` — same contract as
+    ///   [`CommandPool::from_raw`](struct.CommandPool.html#method.from_raw).
+    /// * if `reset` is `IndividualReset`, `flags` must include the individual
+    ///   command buffer reset flag.
+    pub unsafe fn new(
+        family: gfx_hal::queue::QueueFamilyId,
+        capability: C,
+        reset: R,
+        flags: gfx_hal::pool::CommandPoolCreateFlags,
+    ) -> Self {
+        CommandPoolManager {
+            family,
+            capability,
+            reset,
+            flags,
+            pools: KeyedSlots::new(),
+        }
+    }
+
+    /// Acquire the pool owned by the calling thread, creating it first if
+    /// this is the thread's first acquisition.
+    pub fn acquire(&self, device: &impl gfx_hal::Device<B>) -> CommandPoolGuard<'_, B, C, R> {
+        let thread_id = std::thread::current().id();
+
+        self.pools.ensure(thread_id, || unsafe {
+            let raw = device.create_command_pool(self.family, self.flags);
+            OwningCommandPool::from_inner(
+                CommandPool::from_raw(raw, self.capability, self.reset, self.family),
+                PrimaryLevel,
+            )
+        });
+
+        CommandPoolGuard {
+            manager: self,
+            thread_id,
+        }
+    }
+
+    /// Tear down every per-thread pool.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`OwningCommandPool::dispose`](struct.OwningCommandPool.html#method.dispose).
+    pub unsafe fn dispose(self, device: &impl gfx_hal::Device<B>) {
+        for pool in self.pools.into_values() {
+            pool.dispose(device);
+        }
+    }
+}
+
+impl<B, C> CommandPoolManager<B, C, IndividualReset>
+where
+    B: gfx_hal::Backend,
+    C: Capability + Copy,
+{
+    /// Sweep every per-thread pool, recycling buffers whose submission has retired.
+    pub fn maintain(&self, last_done_index: SubmissionIndex) {
+        self.pools.for_each(|pool| pool.maintain(last_done_index));
+    }
+}
+
+/// Grants the calling thread access to its pool, handed out by
+/// [`CommandPoolManager::acquire`](struct.CommandPoolManager.html#method.acquire).
+pub struct CommandPoolGuard<'a, B: gfx_hal::Backend, C, R> {
+    manager: &'a CommandPoolManager<B, C, R>,
+    thread_id: std::thread::ThreadId,
+}
+
+impl<'a, B, C, R> CommandPoolGuard<'a, B, C, R>
+where
+    B: gfx_hal::Backend,
+{
+    /// Run `f` with exclusive access to the calling thread's pool.
+    pub fn with_pool<T>(&self, f: impl FnOnce(&mut OwningCommandPool<B, C, PrimaryLevel, R>) -> T) -> T {
+        self.manager.pools.with(self.thread_id, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retire, KeyedSlots};
+
+    #[test]
+    fn keyed_slots_ensure_creates_once_per_key() {
+        let slots: KeyedSlots<u32, u32> = KeyedSlots::new();
+        let calls = std::cell::Cell::new(0);
+
+        slots.ensure(1, || {
+            calls.set(calls.get() + 1);
+            10
+        });
+        slots.ensure(1, || {
+            calls.set(calls.get() + 1);
+            20
+        });
+
+        assert_eq!(calls.get(), 1);
+        slots.with(1, |v| assert_eq!(*v, 10));
+    }
+
+    #[test]
+    fn keyed_slots_distinct_keys_do_not_block_each_other() {
+        use std::sync::{mpsc, Arc};
+        use std::time::{Duration, Instant};
+
+        let slots = Arc::new(KeyedSlots::<u32, ()>::new());
+        slots.ensure(1, || ());
+        slots.ensure(2, || ());
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let slots_bg = slots.clone();
+        let handle = std::thread::spawn(move || {
+            slots_bg.with(1, |_| {
+                ready_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+            });
+        });
+
+        ready_rx.recv().unwrap();
+        let start = Instant::now();
+        slots.with(2, |_| {});
+
+        assert!(
+            start.elapsed() < Duration::from_millis(100),
+            "acquiring an unrelated key blocked on another thread's lock"
+        );
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn retire_takes_entries_at_or_before_last_done() {
+        let mut pending = vec![(1, 1), (2, 3), (3, 2), (4, 5)];
+
+        let mut done = retire(&mut pending, 3);
+        done.sort();
+
+        assert_eq!(done, vec![1, 2, 3]);
+        assert_eq!(pending, vec![(4, 5)]);
+    }
+
+    #[test]
+    fn retire_leaves_entries_after_last_done() {
+        let mut pending = vec![(1, 10)];
+
+        let done = retire(&mut pending, 3);
+
+        assert!(done.is_empty());
+        assert_eq!(pending, vec![(1, 10)]);
+    }
+
+    #[test]
+    fn retire_drops_keep_alive_handles_whose_submission_has_completed() {
+        use std::sync::Arc;
+
+        let tracked = Arc::new(());
+        let mut keep_alive = vec![(tracked.clone(), 1), (tracked.clone(), 5)];
+        assert_eq!(Arc::strong_count(&tracked), 3);
+
+        retire(&mut keep_alive, 3);
+
+        assert_eq!(
+            Arc::strong_count(&tracked),
+            2,
+            "handle for the retired submission should have been dropped"
+        );
+        assert_eq!(keep_alive, vec![(tracked.clone(), 5)]);
+    }
+}